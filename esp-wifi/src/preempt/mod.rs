@@ -0,0 +1,20 @@
+pub mod preempt;
+
+use core::sync::atomic::AtomicBool;
+
+/// Set while the very first context switch has not happened yet. The timer
+/// setup code spins on this so that `setup_timer_isr` only returns once the
+/// scheduler has handed control to the first task.
+pub static FIRST_SWITCH: AtomicBool = AtomicBool::new(true);
+
+/// Priority assigned to a task when none is requested. Kept low so that
+/// latency-sensitive work created with a higher priority preempts it.
+pub const DEFAULT_TASK_PRIORITY: u8 = 1;
+
+/// Highest priority level the scheduler distinguishes. The Wi-Fi MAC task is
+/// expected to run at this level so a CPU-bound user task cannot starve it.
+pub const MAX_TASK_PRIORITY: u8 = 3;
+
+/// Number of timer ticks a task may run before it is forced to yield, unless
+/// it is preempted earlier by a higher-priority task becoming runnable.
+pub const DEFAULT_TIME_SLICE: u32 = 5;
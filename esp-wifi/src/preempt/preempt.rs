@@ -0,0 +1,288 @@
+use core::cell::RefCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::Ordering;
+
+use critical_section::Mutex;
+
+use super::{DEFAULT_TASK_PRIORITY, DEFAULT_TIME_SLICE, FIRST_SWITCH, MAX_TASK_PRIORITY};
+
+#[cfg(feature = "esp32s3")]
+use esp32s3_hal::trapframe::TrapFrame;
+#[cfg(feature = "esp32c2")]
+use esp32c2_hal::interrupt::TrapFrame;
+
+/// Maximum number of tasks the scheduler can track at once.
+pub const MAX_TASKS: usize = 4;
+
+/// Per-task stack size, in bytes.
+pub const TASK_STACK_SIZE: usize = 8192;
+
+#[repr(align(16))]
+struct Stack([u8; TASK_STACK_SIZE]);
+
+impl Stack {
+    const fn new() -> Self {
+        Stack([0; TASK_STACK_SIZE])
+    }
+
+    /// Address of the initial (highest, 16-byte aligned) stack pointer.
+    fn top(&self) -> usize {
+        let base = self.0.as_ptr() as usize;
+        (base + TASK_STACK_SIZE) & !0xf
+    }
+}
+
+struct Task {
+    ctx: TrapFrame,
+    /// Backing stack for the task; `ctx`'s stack pointer points into this.
+    stack: Stack,
+    /// Whether this slot holds a live task.
+    allocated: bool,
+    /// Whether the task is ready to run. A task that is waiting (e.g. blocked
+    /// on a queue) clears this so the scheduler skips it and lower-priority
+    /// tasks get the CPU instead of being starved.
+    runnable: bool,
+    /// Scheduling priority; higher runs first. See [`MAX_TASK_PRIORITY`].
+    priority: u8,
+    /// Number of ticks the task is allowed to run before being rescheduled.
+    slice_reload: u32,
+    /// Ticks left in the current slice; `0` means the slice is exhausted.
+    slice_remaining: u32,
+}
+
+impl Task {
+    const fn empty() -> Self {
+        Self {
+            // Safety: an unallocated task's context is never restored.
+            ctx: unsafe { MaybeUninit::zeroed().assume_init() },
+            stack: Stack::new(),
+            allocated: false,
+            runnable: false,
+            priority: DEFAULT_TASK_PRIORITY,
+            slice_reload: DEFAULT_TIME_SLICE,
+            slice_remaining: DEFAULT_TIME_SLICE,
+        }
+    }
+}
+
+struct SchedulerState {
+    tasks: [Task; MAX_TASKS],
+    /// Index of the currently running task.
+    current: usize,
+}
+
+impl SchedulerState {
+    const fn new() -> Self {
+        const EMPTY: Task = Task::empty();
+        let mut tasks = [EMPTY; MAX_TASKS];
+        // Slot 0 is reserved for the bootstrap (main) context that is already
+        // running when the scheduler starts. Its `ctx` is filled in by the
+        // first context save; reserving it keeps workers off slot 0 and gives
+        // the scheduler an always-runnable context to fall back on.
+        tasks[0].allocated = true;
+        tasks[0].runnable = true;
+        Self { tasks, current: 0 }
+    }
+
+    /// Pick the next task to run: the highest-priority *runnable* task,
+    /// iterating round-robin within a priority level so tasks of equal priority
+    /// share the CPU fairly. Blocked tasks are not candidates, so a waiting
+    /// high-priority task releases the CPU to lower-priority work. Returns
+    /// `None` when nothing is runnable.
+    fn pick_next(&self) -> Option<usize> {
+        let mut best = None;
+        let mut best_priority = 0u8;
+
+        // Scan every slot once, starting just after the current task (and ending
+        // back on it) so that equal-priority tasks rotate instead of the first
+        // one always winning.
+        for offset in 1..=MAX_TASKS {
+            let idx = (self.current + offset) % MAX_TASKS;
+            let task = &self.tasks[idx];
+            if !task.allocated || !task.runnable {
+                continue;
+            }
+
+            if best.is_none() || task.priority > best_priority {
+                best = Some(idx);
+                best_priority = task.priority;
+            }
+        }
+
+        best
+    }
+
+    /// True if any *runnable* task outranks the running task.
+    fn higher_priority_runnable(&self) -> bool {
+        let current_priority = self.tasks[self.current].priority;
+        self.tasks.iter().enumerate().any(|(idx, task)| {
+            idx != self.current
+                && task.allocated
+                && task.runnable
+                && task.priority > current_priority
+        })
+    }
+}
+
+static SCHEDULER: Mutex<RefCell<SchedulerState>> = Mutex::new(RefCell::new(SchedulerState::new()));
+
+/// Landing pad for a task entry that returns. There is no owner to report
+/// completion to, so park the CPU; a return from a task is a bug in the caller.
+extern "C" fn task_exit() -> ! {
+    loop {}
+}
+
+/// Write the initial register state for a freshly created task into `ctx` so it
+/// starts executing `entry(param)` on its own stack.
+#[cfg(feature = "esp32s3")]
+fn setup_task_context(ctx: &mut TrapFrame, entry: usize, param: usize, stack_top: usize) {
+    // Windowed ABI, entered as if by `call4`: PS.CALLINC is 1, so the task's
+    // `entry` rotates the window by four registers. PC is the entry point, A1
+    // the stack pointer, and the first argument is passed in A6 (the task's
+    // a2). A4 (the task's a0) holds a call4-encoded return address so a task
+    // that returns lands in `task_exit` instead of jumping to 0. The register
+    // window is re-established by the HAL's trapframe restore path on the way
+    // out of the switch.
+    ctx.PC = entry as u32;
+    ctx.A4 = (task_exit as usize as u32 & 0x3FFF_FFFF) | 0x4000_0000;
+    ctx.A1 = stack_top as u32;
+    ctx.A6 = param as u32;
+    ctx.PS = 0x0004_0000 | (1 << 16); // WOE set, CALLINC = 1, INTLEVEL 0 (interrupts enabled)
+}
+
+/// Write the initial register state for a freshly created task into `ctx` so it
+/// starts executing `entry(param)` on its own stack.
+#[cfg(feature = "esp32c2")]
+fn setup_task_context(ctx: &mut TrapFrame, entry: usize, param: usize, stack_top: usize) {
+    // RISC-V calling convention: pc is the entry point, sp the stack pointer,
+    // and the first argument is passed in a0. ra points at `task_exit` so a task
+    // that returns parks instead of jumping to 0.
+    ctx.pc = entry;
+    ctx.ra = task_exit as usize;
+    ctx.sp = stack_top;
+    ctx.a0 = param;
+}
+
+/// Create a task with the default priority and time slice.
+pub fn task_create(entry: extern "C" fn(*mut core::ffi::c_void), param: *mut core::ffi::c_void) -> usize {
+    task_create_prio(entry, param, DEFAULT_TASK_PRIORITY, DEFAULT_TIME_SLICE)
+}
+
+/// Create a task with an explicit priority and time slice. `priority` is
+/// clamped to [`MAX_TASK_PRIORITY`]; a larger slice lets a task run for more
+/// ticks before it is rescheduled.
+pub fn task_create_prio(
+    entry: extern "C" fn(*mut core::ffi::c_void),
+    param: *mut core::ffi::c_void,
+    priority: u8,
+    time_slice: u32,
+) -> usize {
+    let priority = priority.min(MAX_TASK_PRIORITY);
+    let time_slice = time_slice.max(1);
+
+    critical_section::with(|cs| {
+        let mut state = SCHEDULER.borrow_ref_mut(cs);
+        let idx = state
+            .tasks
+            .iter()
+            .position(|task| !task.allocated)
+            .expect("no free task slot");
+
+        let task = &mut state.tasks[idx];
+        task.allocated = true;
+        task.runnable = true;
+        task.priority = priority;
+        task.slice_reload = time_slice;
+        task.slice_remaining = time_slice;
+
+        let stack_top = task.stack.top();
+        setup_task_context(&mut task.ctx, entry as usize, param as usize, stack_top);
+        idx
+    })
+}
+
+/// Mark a task as waiting so the scheduler skips it until it is unblocked. A
+/// task blocking on a resource calls this to release the CPU.
+pub fn task_block(idx: usize) {
+    critical_section::with(|cs| {
+        SCHEDULER.borrow_ref_mut(cs).tasks[idx].runnable = false;
+    });
+}
+
+/// Mark a previously blocked task as runnable again.
+pub fn task_unblock(idx: usize) {
+    critical_section::with(|cs| {
+        SCHEDULER.borrow_ref_mut(cs).tasks[idx].runnable = true;
+    });
+}
+
+/// Switch tasks from a timer tick.
+///
+/// Decrements the running task's slice; a reschedule happens when the slice is
+/// exhausted, a higher-priority task is runnable, or the running task has
+/// blocked. Otherwise the running task keeps the CPU.
+pub fn task_switch(context: &mut TrapFrame) {
+    critical_section::with(|cs| {
+        let mut state = SCHEDULER.borrow_ref_mut(cs);
+
+        let preempt = state.higher_priority_runnable();
+        let current = state.current;
+        let blocked = !state.tasks[current].runnable;
+        let slice_expired = {
+            let task = &mut state.tasks[current];
+            task.slice_remaining = task.slice_remaining.saturating_sub(1);
+            task.slice_remaining == 0
+        };
+
+        if !preempt && !slice_expired && !blocked {
+            return;
+        }
+
+        reschedule(&mut state, context);
+    });
+
+    FIRST_SWITCH.store(false, Ordering::Relaxed);
+}
+
+/// Switch tasks immediately, regardless of the remaining time slice. Used by
+/// `yield_task` so a cooperatively yielding task hands the CPU over right away.
+pub fn task_switch_yield(context: &mut TrapFrame) {
+    critical_section::with(|cs| {
+        let mut state = SCHEDULER.borrow_ref_mut(cs);
+        reschedule(&mut state, context);
+    });
+
+    FIRST_SWITCH.store(false, Ordering::Relaxed);
+}
+
+fn reschedule(state: &mut SchedulerState, context: &mut TrapFrame) {
+    let next = match state.pick_next() {
+        Some(next) => next,
+        None => {
+            // Nothing is runnable. Keep the current frame, so the interrupted
+            // task resumes even if it has just blocked; the next tick or yield
+            // reschedules again. In practice the reserved bootstrap slot 0 stays
+            // runnable and is selected above, so this only happens if every
+            // task including the idle context has blocked.
+            return;
+        }
+    };
+
+    if next == state.current {
+        // Still the best choice; just refresh its slice.
+        let task = &mut state.tasks[next];
+        task.slice_remaining = task.slice_reload;
+        return;
+    }
+
+    // Save the outgoing context and restore the incoming one. The register
+    // save/restore asm lives in the interrupt entry/exit; here we swap the
+    // saved trap frames.
+    let current = state.current;
+    state.tasks[current].ctx = *context;
+
+    let task = &mut state.tasks[next];
+    task.slice_remaining = task.slice_reload;
+    *context = task.ctx;
+    state.current = next;
+}
@@ -1,14 +1,17 @@
 use core::cell::RefCell;
 
 use critical_section::Mutex;
-use esp32c2 as pac;
 use esp32c2_hal as hal;
 use esp32c2_hal::interrupt::TrapFrame;
 use esp32c2_hal::prelude::*;
+use esp32c2_hal::system::SoftwareInterrupt;
 use hal::peripherals::Interrupt;
-use hal::systimer::{Alarm, Periodic, Target};
+use hal::systimer::{Alarm, Periodic, SystemTimer, Target};
 
-use crate::{binary, preempt::preempt::task_switch};
+use crate::{
+    binary,
+    preempt::preempt::{task_switch, task_switch_yield},
+};
 use crate::{trace, unwrap};
 
 pub const TICKS_PER_SECOND: u64 = 16_000_000;
@@ -17,9 +20,41 @@ pub const COUNTER_BIT_MASK: u64 = 0x000F_FFFF_FFFF_FFFF;
 
 const TIMER_DELAY: fugit::HertzU32 = fugit::HertzU32::from_raw(crate::CONFIG.tick_rate_hz);
 
+/// Convert a duration in milliseconds to the equivalent number of timer ticks.
+pub fn millis_to_ticks(millis: u64) -> u64 {
+    millis * TICKS_PER_SECOND / 1000
+}
+
+/// Convert a duration in microseconds to the equivalent number of timer ticks.
+pub fn micros_to_ticks(micros: u64) -> u64 {
+    micros * TICKS_PER_SECOND / 1_000_000
+}
+
+/// Convert a number of timer ticks back into whole milliseconds.
+pub fn ticks_to_millis(ticks: u64) -> u64 {
+    ticks / (TICKS_PER_SECOND / 1000)
+}
+
+/// Number of ticks between `earlier` and `later`, accounting for the counter
+/// wrapping at [`COUNTER_BIT_MASK`]. The c2 systimer counter is only 52 bits
+/// wide, so the masked subtraction is what makes comparisons stay correct once
+/// the counter rolls over.
+pub fn ticks_elapsed(earlier: u64, later: u64) -> u64 {
+    later.wrapping_sub(earlier) & COUNTER_BIT_MASK
+}
+
 static ALARM0: Mutex<RefCell<Option<Alarm<Periodic, 0>>>> = Mutex::new(RefCell::new(None));
 
-pub fn setup_timer_isr(systimer: Alarm<Target, 0>) {
+static SOFTWARE_INTERRUPT3: Mutex<RefCell<Option<SoftwareInterrupt<3>>>> =
+    Mutex::new(RefCell::new(None));
+
+pub fn setup_timer_isr(systimer: Alarm<Target, 0>, software_interrupt3: SoftwareInterrupt<3>) {
+    critical_section::with(|cs| {
+        SOFTWARE_INTERRUPT3
+            .borrow_ref_mut(cs)
+            .replace(software_interrupt3)
+    });
+
     let alarm0 = systimer.into_periodic();
     alarm0.set_period(TIMER_DELAY.into());
     alarm0.clear_interrupt();
@@ -158,12 +193,12 @@ fn SYSTIMER_TARGET0(trap_frame: &mut TrapFrame) {
 
 #[interrupt]
 fn FROM_CPU_INTR3(trap_frame: &mut TrapFrame) {
-    unsafe {
+    critical_section::with(|cs| {
         // clear ETS_FROM_CPU_INTR3
-        (&*pac::SYSTEM::PTR)
-            .cpu_intr_from_cpu_3
-            .modify(|_, w| w.cpu_intr_from_cpu_3().clear_bit());
-    }
+        if let Some(swi) = SOFTWARE_INTERRUPT3.borrow_ref(cs).as_ref() {
+            swi.reset();
+        }
+    });
 
     critical_section::with(|cs| {
         let mut alarm0 = ALARM0.borrow_ref_mut(cs);
@@ -173,36 +208,173 @@ fn FROM_CPU_INTR3(trap_frame: &mut TrapFrame) {
         alarm0.clear_interrupt();
     });
 
-    task_switch(trap_frame);
+    task_switch_yield(trap_frame);
 }
 
 pub fn yield_task() {
-    unsafe {
-        (&*pac::SYSTEM::PTR)
-            .cpu_intr_from_cpu_3
-            .modify(|_, w| w.cpu_intr_from_cpu_3().set_bit());
+    critical_section::with(|cs| {
+        if let Some(swi) = SOFTWARE_INTERRUPT3.borrow_ref(cs).as_ref() {
+            swi.raise();
+        }
+    });
+}
+
+#[cfg(feature = "embassy")]
+pub use self::embassy_driver::init_embassy;
+
+/// An [`embassy_time::driver::Driver`] backed by the systimer that also drives
+/// the preemptive scheduler.
+///
+/// `now()` reads the same monotonic counter as [`get_systimer_count`], scaled
+/// to the embassy tick rate, while a second systimer alarm fires the
+/// `set_alarm` callbacks. The periodic [`TIMER_DELAY`] alarm keeps driving
+/// `task_switch` independently.
+#[cfg(feature = "embassy")]
+mod embassy_driver {
+    use core::cell::Cell;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    use critical_section::Mutex;
+    use embassy_time::driver::{AlarmHandle, Driver};
+    use hal::systimer::{Alarm, Target};
+
+    use super::{get_systimer_count, hal, unwrap, Interrupt, TICKS_PER_SECOND};
+
+    struct AlarmState {
+        callback: Cell<Option<(fn(*mut ()), *mut ())>>,
+    }
+
+    unsafe impl Send for AlarmState {}
+
+    /// Set once the single hardware alarm has been handed out.
+    static ALARM_ALLOCATED: AtomicBool = AtomicBool::new(false);
+
+    static ALARM1: Mutex<core::cell::RefCell<Option<Alarm<Target, 1>>>> =
+        Mutex::new(core::cell::RefCell::new(None));
+
+    static ALARM_STATE: Mutex<AlarmState> = Mutex::new(AlarmState {
+        callback: Cell::new(None),
+    });
+
+    /// Register the spare systimer alarm used for `embassy_time` wake-ups.
+    pub fn init_embassy(alarm1: Alarm<Target, 1>) {
+        alarm1.interrupt_enable(true);
+        critical_section::with(|cs| ALARM1.borrow_ref_mut(cs).replace(alarm1));
+
+        unwrap!(esp32c2_hal::interrupt::enable(
+            Interrupt::SYSTIMER_TARGET1,
+            hal::interrupt::Priority::Priority1,
+        ));
+    }
+
+    struct EspWifiTimeDriver;
+
+    impl Driver for EspWifiTimeDriver {
+        fn now(&self) -> u64 {
+            // Widen to u128 for the rescale: at 16 MHz the 64-bit product would
+            // otherwise overflow within a couple of weeks of uptime.
+            (get_systimer_count() as u128 * embassy_time::TICK_HZ as u128
+                / TICKS_PER_SECOND as u128) as u64
+        }
+
+        unsafe fn allocate_alarm(&self) -> Option<AlarmHandle> {
+            // Only one hardware alarm is wired up, so hand out handle 0 exactly
+            // once; further requests get `None` per the `Driver` contract.
+            if ALARM_ALLOCATED.swap(true, Ordering::Relaxed) {
+                return None;
+            }
+            Some(AlarmHandle::new(0))
+        }
+
+        fn set_alarm_callback(&self, _alarm: AlarmHandle, callback: fn(*mut ()), ctx: *mut ()) {
+            critical_section::with(|cs| {
+                ALARM_STATE.borrow(cs).callback.set(Some((callback, ctx)));
+            });
+        }
+
+        fn set_alarm(&self, _alarm: AlarmHandle, timestamp: u64) -> bool {
+            critical_section::with(|cs| {
+                let now = self.now();
+                if timestamp <= now {
+                    return false;
+                }
+
+                let ticks = (timestamp as u128 * TICKS_PER_SECOND as u128
+                    / embassy_time::TICK_HZ as u128) as u64;
+
+                // Re-check against the live counter: the conversion plus the
+                // time spent getting here may have pushed the target into the
+                // past, and an already-elapsed absolute target would otherwise
+                // not fire until the 52-bit counter wraps.
+                if ticks <= get_systimer_count() {
+                    return false;
+                }
+
+                if let Some(alarm) = ALARM1.borrow_ref_mut(cs).as_mut() {
+                    alarm.set_target(ticks);
+                    alarm.clear_interrupt();
+                }
+                true
+            })
+        }
+    }
+
+    embassy_time::time_driver_impl!(static DRIVER: EspWifiTimeDriver = EspWifiTimeDriver);
+
+    #[hal::macros::interrupt]
+    fn SYSTIMER_TARGET1() {
+        let callback = critical_section::with(|cs| {
+            if let Some(alarm) = ALARM1.borrow_ref_mut(cs).as_mut() {
+                alarm.clear_interrupt();
+            }
+            ALARM_STATE.borrow(cs).callback.get()
+        });
+
+        if let Some((func, ctx)) = callback {
+            func(ctx);
+        }
     }
 }
 
 /// Current systimer count value
 /// A tick is 1 / 16_000_000 seconds
 pub fn get_systimer_count() -> u64 {
-    critical_section::with(|_| unsafe {
-        let systimer = &(*pac::SYSTIMER::ptr());
+    // `SystemTimer::now` performs the same latch-and-read sequence on unit 0
+    // (set op bit 30, poll the valid bit 29, then read the lo/hi registers).
+    SystemTimer::now()
+}
 
-        systimer.unit0_op.write(|w| w.bits(1 << 30));
+/// Current value of the high-resolution cycle counter. On RISC-V this is the
+/// 16 MHz systimer, which already counts monotonically within its 52-bit range.
+pub fn now_cycles() -> u64 {
+    get_systimer_count()
+}
 
-        // wait for value available
-        loop {
-            let valid = (systimer.unit0_op.read().bits() >> 29) & 1;
-            if valid != 0 {
-                break;
-            }
-        }
+/// Convert a raw cycle count into nanoseconds at [`TICKS_PER_SECOND`].
+pub fn cycles_to_nanos(cycles: u64) -> u64 {
+    // Widen to u128: an absolute `now_cycles()` exceeds the safe u64 range for
+    // the `* 1_000_000_000` multiply after about 19 minutes of 16 MHz ticks.
+    (cycles as u128 * 1_000_000_000 / TICKS_PER_SECOND as u128) as u64
+}
 
-        let value_lo = systimer.unit0_value_lo.read().bits() as u64;
-        let value_hi = (systimer.unit0_value_hi.read().bits() as u64) << 32;
+/// A high-resolution timestamp taken from the systimer, with nanosecond
+/// resolution. Use it to measure ISR latency or packet-handling time without
+/// the tick-granularity loss of the scheduler tick.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant {
+    cycles: u64,
+}
 
-        (value_lo | value_hi) as u64
-    })
+impl Instant {
+    /// Capture the current systimer count.
+    pub fn now() -> Self {
+        Self {
+            cycles: now_cycles(),
+        }
+    }
+
+    /// Nanoseconds elapsed since `earlier`, handling the 52-bit counter wrap.
+    pub fn duration_since(&self, earlier: Instant) -> u64 {
+        cycles_to_nanos(ticks_elapsed(earlier.cycles, self.cycles))
+    }
 }